@@ -5,13 +5,140 @@
 
 use crate::bam::HeaderView;
 use linear_map::LinearMap;
-use regex::Regex;
+use regex::bytes::Regex as BytesRegex;
 use std::collections::HashMap;
 
+/// A single line of a `Header`, kept in its own parsed representation so that
+/// individual records can be inspected and edited without re-parsing the whole
+/// header on every call.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum HeaderLine {
+    /// An `@CO` free-text comment line.
+    Comment(Vec<u8>),
+    /// Any other two-letter record type (e.g. `@HD`, `@SQ`, `@RG`, `@PG`), with its
+    /// `tag:value` pairs in file order. Duplicate tags are preserved.
+    Record {
+        record_type: Vec<u8>,
+        tags: Vec<(Vec<u8>, Vec<u8>)>,
+    },
+    /// A line that could not be parsed into the above (e.g. it has no `@XX` record
+    /// type, or a field that doesn't match `tag:value`). Kept verbatim, along with why
+    /// it failed to parse, so a broken header can still be round-tripped byte-for-byte
+    /// and [`records`](Header::records) can report the actual cause.
+    Raw { bytes: Vec<u8>, reason: RawLineReason },
+}
+
+/// Why a [`HeaderLine::Raw`] line could not be parsed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum RawLineReason {
+    /// The line has no `@XX` record type.
+    MissingRecordType,
+    /// The given field doesn't match the `tag:value` pattern.
+    InvalidTag(Vec<u8>),
+}
+
+impl HeaderLine {
+    fn parse(line: &[u8], rec_type_re: &BytesRegex, tag_re: &BytesRegex) -> Self {
+        let record_type = match rec_type_re.captures(line).and_then(|cap| cap.get(1)) {
+            Some(m) => m.as_bytes(),
+            None => {
+                return HeaderLine::Raw {
+                    bytes: line.to_owned(),
+                    reason: RawLineReason::MissingRecordType,
+                }
+            }
+        };
+
+        if record_type == b"CO" {
+            let text = line.splitn(2, |&b| b == b'\t').nth(1).unwrap_or(&[]);
+            return HeaderLine::Comment(text.to_owned());
+        }
+
+        let mut tags = Vec::new();
+        for field in line
+            .split(|&b| b == b'\t')
+            .skip(1)
+            .filter(|f| !f.is_empty())
+        {
+            match tag_re
+                .captures(field)
+                .and_then(|cap| Some((cap.get(1)?, cap.get(2)?)))
+            {
+                Some((tag, value)) => {
+                    tags.push((tag.as_bytes().to_owned(), value.as_bytes().to_owned()))
+                }
+                None => {
+                    return HeaderLine::Raw {
+                        bytes: line.to_owned(),
+                        reason: RawLineReason::InvalidTag(field.to_owned()),
+                    }
+                }
+            }
+        }
+
+        HeaderLine::Record {
+            record_type: record_type.to_owned(),
+            tags,
+        }
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            HeaderLine::Comment(text) => [&b"@CO"[..], text].join(&b'\t'),
+            HeaderLine::Record { record_type, tags } => {
+                let mut out = Vec::new();
+                out.push(b'@');
+                out.extend(record_type);
+                for (tag, value) in tags {
+                    out.push(b'\t');
+                    out.extend(tag);
+                    out.push(b':');
+                    out.extend(value);
+                }
+                out
+            }
+            HeaderLine::Raw { bytes, .. } => bytes.clone(),
+        }
+    }
+
+    fn to_parsed_record(&self) -> Result<ParsedRecord, HeaderParseError> {
+        match self {
+            HeaderLine::Comment(text) => Ok(ParsedRecord::Comment(
+                String::from_utf8(text.clone()).map_err(|_| HeaderParseError::InvalidUtf8)?,
+            )),
+            HeaderLine::Record { record_type, tags } => {
+                let record_type = String::from_utf8(record_type.clone())
+                    .map_err(|_| HeaderParseError::InvalidUtf8)?;
+                let tags = tags
+                    .iter()
+                    .map(|(tag, value)| {
+                        let tag = String::from_utf8(tag.clone())
+                            .map_err(|_| HeaderParseError::InvalidUtf8)?;
+                        let value = String::from_utf8(value.clone())
+                            .map_err(|_| HeaderParseError::InvalidUtf8)?;
+                        Ok((tag, value))
+                    })
+                    .collect::<Result<Vec<_>, HeaderParseError>>()?;
+                Ok(ParsedRecord::Record { record_type, tags })
+            }
+            HeaderLine::Raw { bytes, reason } => {
+                let line = String::from_utf8_lossy(bytes).into_owned();
+                Err(match reason {
+                    RawLineReason::MissingRecordType => HeaderParseError::MissingRecordType(line),
+                    RawLineReason::InvalidTag(field) => HeaderParseError::InvalidTag(
+                        line,
+                        String::from_utf8_lossy(field).into_owned(),
+                    ),
+                })
+            }
+        }
+    }
+}
+
 /// A BAM header.
 #[derive(Debug, Clone)]
 pub struct Header {
-    records: Vec<Vec<u8>>,
+    records: Vec<HeaderLine>,
 }
 
 impl Default for Header {
@@ -29,71 +156,210 @@ impl Header {
     }
 
     pub fn from_template(header: &HeaderView) -> Self {
-        let mut record = header.as_bytes().to_owned();
+        let mut bytes = header.as_bytes().to_owned();
         // Strip off any trailing newline character.
         // Otherwise there could be a blank line in the
         // header which samtools (<=1.6) will complain
         // about
-        while let Some(&last_char) = record.last() {
+        while let Some(&last_char) = bytes.last() {
             if last_char == b'\n' {
-                record.pop();
+                bytes.pop();
             } else {
                 break;
             }
         }
-        Header {
-            records: vec![record],
-        }
+
+        Self::from_bytes(&bytes)
+    }
+
+    /// Parse a header from its raw, newline-separated text representation.
+    fn from_bytes(bytes: &[u8]) -> Self {
+        let rec_type_re = BytesRegex::new(r"^@([A-Za-z][A-Za-z0-9])(?:\t|$)").unwrap();
+        let tag_re = BytesRegex::new(r"^([A-Za-z][A-Za-z0-9]):([ -~]*)$").unwrap();
+
+        let records = bytes
+            .split(|&b| b == b'\n')
+            .filter(|line| !line.is_empty())
+            .map(|line| HeaderLine::parse(line, &rec_type_re, &tag_re))
+            .collect();
+
+        Header { records }
     }
 
     /// Add a record to the header.
     pub fn push_record(&mut self, record: &HeaderRecord<'_>) -> &mut Self {
-        self.records.push(record.to_bytes());
+        let tags = record
+            .tags
+            .iter()
+            .map(|(tag, value)| (tag.to_vec(), value.clone()))
+            .collect();
+        self.records.push(HeaderLine::Record {
+            record_type: record.rec_type[1..].to_vec(),
+            tags,
+        });
         self
     }
 
     /// Add a comment to the header.
     pub fn push_comment(&mut self, comment: &[u8]) -> &mut Self {
-        self.records.push([&b"@CO"[..], comment].join(&b'\t'));
+        self.records.push(HeaderLine::Comment(comment.to_owned()));
+        self
+    }
+
+    /// Remove all records of the given two-letter type (e.g. `b"PG"`), leaving `@CO`
+    /// comments and every other record type untouched.
+    pub fn remove_records(&mut self, rec_type: &[u8]) -> &mut Self {
+        self.records.retain(|line| match line {
+            HeaderLine::Record { record_type, .. } => record_type != rec_type,
+            _ => true,
+        });
         self
     }
 
+    /// Keep only the records for which `f` returns `true`.
+    ///
+    /// Lines that could not be parsed (see [`records`](Header::records)) are always
+    /// kept, since there is nothing meaningful to pass to `f`.
+    pub fn retain<F>(&mut self, mut f: F)
+    where
+        F: FnMut(&ParsedRecord) -> bool,
+    {
+        self.records
+            .retain(|line| line.to_parsed_record().map(|rec| f(&rec)).unwrap_or(true));
+    }
+
+    /// Get mutable access to the tags of every record of the given two-letter type,
+    /// in file order, so a program can rewrite an `@SQ` length or look up the last
+    /// `@PG` record's `ID` to chain a new one onto it. Records cannot be removed
+    /// through the returned views; use [`retain`](Header::retain) or
+    /// [`remove_records`](Header::remove_records) for that.
+    pub fn find_records_mut(&mut self, rec_type: &[u8]) -> Vec<HeaderRecordTags<'_>> {
+        self.records
+            .iter_mut()
+            .filter_map(|line| match line {
+                HeaderLine::Record { record_type, tags } if record_type == rec_type => {
+                    Some(HeaderRecordTags { tags })
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
     pub fn to_bytes(&self) -> Vec<u8> {
-        self.records.join(&b'\n')
+        self.records
+            .iter()
+            .map(HeaderLine::to_bytes)
+            .collect::<Vec<_>>()
+            .join(&b'\n')
     }
 
+    /// Collapse the header's non-comment records into a `HashMap` keyed by record
+    /// type.
+    ///
+    /// This drops record order, duplicate-tag order, and `@CO` comment lines. It is
+    /// also lossy rather than strict: a line that doesn't parse (no `@XX` record type,
+    /// a field that isn't `tag:value`, or non-UTF-8 bytes) is silently omitted instead
+    /// of raising an error. Use [`records`](Header::records) if you need to detect a
+    /// malformed header or preserve order, duplicates, and comments.
     pub fn to_hashmap(&self) -> HashMap<String, Vec<LinearMap<String, String>>> {
         let mut header_map = HashMap::default();
 
-        let rec_type_re = Regex::new(r"@([A-Z][A-Z])").unwrap();
-        let tag_re = Regex::new(r"([A-Za-z][A-Za-z0-9]):([ -~]+)").unwrap();
-
-        let header_string = String::from_utf8(self.to_bytes()).unwrap();
-
-        for line in header_string.split('\n').filter(|x| !x.is_empty()) {
-            let parts: Vec<_> = line.split('\t').filter(|x| !x.is_empty()).collect();
-            // assert!(rec_type_re.is_match(parts[0]));
-            let record_type = rec_type_re
-                .captures(parts[0])
-                .unwrap()
-                .get(1)
-                .unwrap()
-                .as_str()
-                .to_owned();
-            let mut field = LinearMap::default();
-            for part in parts.iter().skip(1) {
-                let cap = tag_re.captures(part).unwrap();
-                let tag = cap.get(1).unwrap().as_str().to_owned();
-                let value = cap.get(2).unwrap().as_str().to_owned();
-                field.insert(tag, value);
+        for line in &self.records {
+            if let HeaderLine::Record { record_type, tags } = line {
+                let record_type = String::from_utf8_lossy(record_type).into_owned();
+                let mut field = LinearMap::default();
+                for (tag, value) in tags {
+                    field.insert(
+                        String::from_utf8_lossy(tag).into_owned(),
+                        String::from_utf8_lossy(value).into_owned(),
+                    );
+                }
+                header_map
+                    .entry(record_type)
+                    .or_insert_with(Vec::new)
+                    .push(field);
             }
-            header_map
-                .entry(record_type)
-                .or_insert_with(Vec::new)
-                .push(field);
         }
         header_map
     }
+
+    /// Parse the header into its records, in the order they appear in the file.
+    ///
+    /// Unlike [`to_hashmap`](Header::to_hashmap), which silently skips lines it cannot
+    /// parse, this reports a malformed header (invalid UTF-8, a tag that doesn't match
+    /// `tag:value`, or a missing record type) as a [`HeaderParseError`], without
+    /// panicking. It also preserves record order,
+    /// duplicate tags, and free-text `@CO` comment lines, none of which survive
+    /// `to_hashmap`'s collapse into a `HashMap`.
+    pub fn records(&self) -> Result<Vec<ParsedRecord>, HeaderParseError> {
+        self.records
+            .iter()
+            .map(HeaderLine::to_parsed_record)
+            .collect()
+    }
+}
+
+/// Mutable view over a single header record's tags, returned by
+/// [`Header::find_records_mut`].
+#[derive(Debug)]
+pub struct HeaderRecordTags<'a> {
+    tags: &'a mut Vec<(Vec<u8>, Vec<u8>)>,
+}
+
+impl<'a> HeaderRecordTags<'a> {
+    /// Get the value of `tag`, if the record has it set.
+    pub fn get(&self, tag: &[u8]) -> Option<&[u8]> {
+        self.tags
+            .iter()
+            .find(|(t, _)| t == tag)
+            .map(|(_, v)| v.as_slice())
+    }
+
+    /// Set the value of `tag`, appending it if the record doesn't already have it.
+    pub fn set(&mut self, tag: &[u8], value: &[u8]) -> &mut Self {
+        match self.tags.iter_mut().find(|(t, _)| t == tag) {
+            Some(entry) => entry.1 = value.to_owned(),
+            None => self.tags.push((tag.to_owned(), value.to_owned())),
+        }
+        self
+    }
+
+    /// Remove `tag`, returning its previous value if it was set.
+    pub fn remove(&mut self, tag: &[u8]) -> Option<Vec<u8>> {
+        let pos = self.tags.iter().position(|(t, _)| t == tag)?;
+        Some(self.tags.remove(pos).1)
+    }
+}
+
+/// A single header record, parsed in file order.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParsedRecord {
+    /// An `@CO` free-text comment line, holding the text after the `@CO` tag.
+    Comment(String),
+    /// Any other two-letter record type (e.g. `@HD`, `@SQ`, `@RG`, `@PG`), with its
+    /// `tag:value` pairs in file order. Duplicate tags are preserved.
+    Record {
+        record_type: String,
+        tags: Vec<(String, String)>,
+    },
+}
+
+quick_error! {
+    /// Error parsing a (possibly malformed) header with [`Header::records`].
+    #[derive(Debug, Clone)]
+    pub enum HeaderParseError {
+        InvalidUtf8 {
+            description("header is not valid UTF-8")
+        }
+        MissingRecordType(line: String) {
+            description("header line has no `@XX` record type")
+            display("header line {:?} has no `@XX` record type", line)
+        }
+        InvalidTag(line: String, field: String) {
+            description("header field does not match the `tag:value` pattern")
+            display("field {:?} in line {:?} does not match the `tag:value` pattern", field, line)
+        }
+    }
 }
 
 /// Header record.
@@ -124,16 +390,65 @@ impl<'a> HeaderRecord<'a> {
         self.tags.push((tag, value.to_string().into_bytes()));
         self
     }
+}
 
-    fn to_bytes(&self) -> Vec<u8> {
-        let mut out = Vec::new();
-        out.extend(self.rec_type.iter());
-        for &(tag, ref value) in self.tags.iter() {
-            out.push(b'\t');
-            out.extend(tag.iter());
-            out.push(b':');
-            out.extend(value.iter());
-        }
-        out
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_to_hashmap_silently_drops_comments_and_unparseable_lines() {
+        let header = Header::from_bytes(b"@HD\tVN:1.6\n@CO\tnote\nnot a header line");
+        let map = header.to_hashmap();
+        assert_eq!(map.len(), 1);
+        assert!(map.contains_key("HD"));
+    }
+
+    #[test]
+    fn test_records_reports_missing_record_type() {
+        let header = Header::from_bytes(b"@HD\tVN:1.6\nnot a header line");
+        match header.records().unwrap_err() {
+            HeaderParseError::MissingRecordType(line) => assert_eq!(line, "not a header line"),
+            err => panic!("expected MissingRecordType, got {:?}", err),
+        }
+    }
+
+    #[test]
+    fn test_records_reports_invalid_tag() {
+        let header = Header::from_bytes(b"@HD\tVN:1.6\tbadfield");
+        match header.records().unwrap_err() {
+            HeaderParseError::InvalidTag(line, field) => {
+                assert_eq!(field, "badfield");
+                assert_eq!(line, "@HD\tVN:1.6\tbadfield");
+            }
+            err => panic!("expected InvalidTag, got {:?}", err),
+        }
+    }
+
+    #[test]
+    fn test_header_edit_round_trip() {
+        let mut header = Header::from_bytes(
+            b"@HD\tVN:1.6\n@SQ\tSN:chr1\tLN:100\n@RG\tID:a\n@RG\tID:a\n@PG\tID:p1\n@CO\tnote",
+        );
+
+        for mut rec in header.find_records_mut(b"SQ") {
+            rec.set(b"LN", b"200");
+        }
+        header.remove_records(b"PG");
+
+        let mut seen_rg_ids = std::collections::HashSet::new();
+        header.retain(|rec| match rec {
+            ParsedRecord::Record { record_type, tags } if record_type == "RG" => {
+                let id = tags.iter().find(|(t, _)| t == "ID").map(|(_, v)| v.clone());
+                seen_rg_ids.insert(id)
+            }
+            _ => true,
+        });
+
+        assert_eq!(
+            header.to_bytes(),
+            b"@HD\tVN:1.6\n@SQ\tSN:chr1\tLN:200\n@RG\tID:a\n@CO\tnote".to_vec()
+        );
+        assert_eq!(header.records().unwrap().len(), 4);
     }
 }
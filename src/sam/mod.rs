@@ -10,18 +10,23 @@ use std::path::Path;
 
 use htslib;
 
-use bam::HeaderView;
 use bam::header;
 use bam::record;
+use bam::Format;
+use bam::HeaderView;
+use tpool::ThreadPool;
 
 /// SAM writer.
 #[derive(Debug)]
 pub struct Writer {
     f: *mut htslib::htsFile,
     header: HeaderView,
+    // Keeps the underlying thread pool alive for as long as this writer uses it; see
+    // `set_thread_pool`.
+    tpool: Option<ThreadPool>,
 }
 
-/// Wrapper for opening a SAM file.
+/// Wrapper for opening a SAM/BAM/CRAM file.
 fn hts_open(path: &ffi::CStr, mode: &[u8]) -> Result<*mut htslib::htsFile, WriterError> {
     let ret = unsafe { htslib::hts_open(path.as_ptr(), ffi::CString::new(mode).unwrap().as_ptr()) };
     if ret.is_null() {
@@ -31,35 +36,90 @@ fn hts_open(path: &ffi::CStr, mode: &[u8]) -> Result<*mut htslib::htsFile, Write
     }
 }
 
+/// Build the htslib write-mode string for `format` and an optional BGZF/CRAM
+/// compression level, e.g. `w`, `wb`, `wc`, `wb6`, or `wz6`. SAM output is only
+/// BGZF-compressed (`wz`) when a level is given.
+fn format_mode(format: Format, compression_level: Option<u32>) -> Result<Vec<u8>, WriterError> {
+    if let Some(level) = compression_level {
+        if level > 9 {
+            return Err(WriterError::InvalidCompressionLevel(level));
+        }
+    }
+
+    let mut mode = vec![b'w'];
+    match format {
+        Format::SAM => {
+            if compression_level.is_some() {
+                mode.push(b'z');
+            }
+        }
+        Format::BAM => mode.push(b'b'),
+        Format::CRAM => mode.push(b'c'),
+    }
+    if let Some(level) = compression_level {
+        mode.extend(level.to_string().into_bytes());
+    }
+    Ok(mode)
+}
+
 impl Writer {
-    /// Create new SAM file writer.
+    /// Create new SAM/BAM/CRAM file writer.
     ///
     /// # Arguments
     ///
     /// * `path` - the path.
     /// * `header` - header definition to use
+    /// * `format` - the format to write (SAM, BAM, or CRAM)
     pub fn from_path<P: AsRef<Path>>(
         path: P,
         header: &header::Header,
+        format: Format,
+    ) -> Result<Self, WriterError> {
+        Self::from_path_with_compression(path, header, format, None)
+    }
+
+    /// Create new SAM/BAM/CRAM file writer, with an explicit BGZF/CRAM compression
+    /// level (`0`, no compression, to `9`, best compression). Only meaningful for
+    /// `Format::BAM`/`Format::CRAM`, or `Format::SAM` if BGZF-compressed SAM output is
+    /// desired.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - the path.
+    /// * `header` - header definition to use
+    /// * `format` - the format to write (SAM, BAM, or CRAM)
+    /// * `compression_level` - the BGZF/CRAM compression level to use
+    pub fn from_path_with_compression<P: AsRef<Path>>(
+        path: P,
+        header: &header::Header,
+        format: Format,
+        compression_level: Option<u32>,
     ) -> Result<Self, WriterError> {
         if let Some(p) = path.as_ref().to_str() {
-            Ok(try!(Self::new(p.as_bytes(), header)))
+            Self::new(p.as_bytes(), header, format, compression_level)
         } else {
             Err(WriterError::IOError)
         }
     }
 
-    /// Create a new SAM file at STDOUT.
+    /// Create a new SAM/BAM/CRAM file at STDOUT.
     ///
     /// # Arguments
     ///
     /// * `header` - header definition to use
-    pub fn from_stdout(header: &header::Header) -> Result<Self, WriterError> {
-        Self::new(b"-", header)
+    /// * `format` - the format to write (SAM, BAM, or CRAM)
+    pub fn from_stdout(header: &header::Header, format: Format) -> Result<Self, WriterError> {
+        Self::new(b"-", header, format, None)
     }
 
-    fn new(path: &[u8], header: &header::Header) -> Result<Self, WriterError> {
-        let f = try!(hts_open(&ffi::CString::new(path).unwrap(), b"w"));
+    fn new(
+        path: &[u8],
+        header: &header::Header,
+        format: Format,
+        compression_level: Option<u32>,
+    ) -> Result<Self, WriterError> {
+        let mode = format_mode(format, compression_level)?;
+        let f = hts_open(&ffi::CString::new(path).unwrap(), &mode)?;
         let header_view = HeaderView::from_header(header);
 
         unsafe {
@@ -68,9 +128,56 @@ impl Writer {
         Ok(Writer {
             f: f,
             header: header_view,
+            tpool: None,
         })
     }
 
+    /// Use a shared thread pool for BGZF/CRAM (de)compression, mirroring what
+    /// samtools does with `--threads`. Without this, all compression on write happens
+    /// on the calling thread.
+    ///
+    /// The pool can be shared between multiple readers/writers; `Writer` holds a
+    /// reference-counted handle to it (see [`tpool::ThreadPool`]), so the pool is only
+    /// torn down once its last handle is dropped, and the caller does not need to
+    /// outlive the writer by hand.
+    ///
+    /// # Arguments
+    ///
+    /// * `pool` - thread pool to use
+    pub fn set_thread_pool(&mut self, pool: &ThreadPool) -> Result<(), ThreadPoolError> {
+        // `hts_set_thread_pool` only reads the pool handle; its signature takes a
+        // `*mut` out of convention with the rest of htslib, not because it mutates it.
+        // `pool` is a shared reference (the pool may be attached to several
+        // readers/writers at once), so go through a `*const` cast rather than forming
+        // an actual `&mut` to the field.
+        let handle = &pool.handle.inner as *const _ as *mut _;
+        let ret = unsafe { htslib::hts_set_thread_pool(self.f, handle) };
+        if ret != 0 {
+            return Err(ThreadPoolError::SetThreadPoolError);
+        }
+        self.tpool = Some(pool.clone());
+        Ok(())
+    }
+
+    /// Set the reference FASTA used to encode CRAM records.
+    ///
+    /// Must be called before writing any records when writing `Format::CRAM` output,
+    /// unless every `@SQ` line in the header already carries an `M5`/`UR` tag.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - path to an (optionally `samtools faidx`-indexed) reference FASTA
+    pub fn set_reference<P: AsRef<Path>>(&mut self, path: P) -> Result<(), WriterError> {
+        let path = path.as_ref().to_str().ok_or(WriterError::IOError)?;
+        let path = ffi::CString::new(path).unwrap();
+        let ret = unsafe { htslib::hts_set_fai_filename(self.f, path.as_ptr()) };
+        if ret == 0 {
+            Ok(())
+        } else {
+            Err(WriterError::IOError)
+        }
+    }
+
     /// Write record to SAM.
     ///
     /// # Arguments
@@ -97,6 +204,10 @@ quick_error! {
     #[derive(Debug, Clone)]
     pub enum WriterError {
         IOError {}
+        InvalidCompressionLevel(level: u32) {
+            description("invalid compression level")
+            display("invalid compression level {} (must be 0-9)", level)
+        }
     }
 }
 
@@ -109,13 +220,62 @@ quick_error! {
     }
 }
 
+quick_error! {
+    #[derive(Debug, Clone)]
+    pub enum ThreadPoolError {
+        SetThreadPoolError {
+            description("error setting thread pool")
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use bam::Read;
-    use bam::Reader;
     use bam::header;
     use bam::record;
+    use bam::Format;
+    use bam::Read;
+    use bam::Reader;
     use sam::Writer;
+    use tpool::ThreadPool;
+
+    use super::{format_mode, WriterError};
+
+    #[test]
+    fn test_format_mode_builds_the_write_mode_string() {
+        assert_eq!(format_mode(Format::SAM, None).unwrap(), b"w");
+        assert_eq!(format_mode(Format::BAM, None).unwrap(), b"wb");
+        assert_eq!(format_mode(Format::CRAM, None).unwrap(), b"wc");
+        assert_eq!(format_mode(Format::BAM, Some(6)).unwrap(), b"wb6");
+        assert_eq!(format_mode(Format::SAM, Some(3)).unwrap(), b"wz3");
+    }
+
+    #[test]
+    fn test_format_mode_rejects_compression_level_above_9() {
+        match format_mode(Format::BAM, Some(10)).unwrap_err() {
+            WriterError::InvalidCompressionLevel(level) => assert_eq!(level, 10),
+            err => panic!("expected InvalidCompressionLevel, got {:?}", err),
+        }
+    }
+
+    #[test]
+    fn test_set_thread_pool_writes_through_pool() {
+        let bamfile = "./test/bam2sam_test.bam";
+        let outfile = "./test/bam2sam_threadpool_out.bam";
+
+        let mut bam_reader = Reader::from_path(bamfile).unwrap();
+        let header = header::Header::from_template(bam_reader.header());
+        let mut writer = Writer::from_path(outfile, &header, Format::BAM).unwrap();
+        let pool = ThreadPool::new(2).unwrap();
+        writer.set_thread_pool(&pool).unwrap();
+        for record in bam_reader.records() {
+            writer.write(&record.unwrap()).unwrap();
+        }
+        drop(writer);
+
+        let mut roundtripped = Reader::from_path(outfile).unwrap();
+        assert!(roundtripped.records().next().is_some());
+    }
 
     #[test]
     fn test_sam_writer_example() {
@@ -125,7 +285,7 @@ mod tests {
         {
             let mut bam_reader = Reader::from_path(bamfile).unwrap(); // internal functions, just unwarp
             let header = header::Header::from_template(bam_reader.header());
-            let mut sam_writer = Writer::from_path(samfile, &header).unwrap();
+            let mut sam_writer = Writer::from_path(samfile, &header, Format::SAM).unwrap();
             for record in bam_reader.records() {
                 if record.is_err() {
                     return false;
@@ -134,9 +294,11 @@ mod tests {
                 match f(&parsed) {
                     None => return true,
                     Some(false) => {}
-                    Some(true) => if let Err(_) = sam_writer.write(&parsed) {
-                        return false;
-                    },
+                    Some(true) => {
+                        if let Err(_) = sam_writer.write(&parsed) {
+                            return false;
+                        }
+                    }
                 }
             }
             true
@@ -150,18 +312,14 @@ mod tests {
         assert!(result);
         let mut expected = Vec::new();
         let mut written = Vec::new();
-        assert!(
-            File::open(expectedfile)
-                .unwrap()
-                .read_to_end(&mut expected)
-                .is_ok()
-        );
-        assert!(
-            File::open(samfile)
-                .unwrap()
-                .read_to_end(&mut written)
-                .is_ok()
-        );
+        assert!(File::open(expectedfile)
+            .unwrap()
+            .read_to_end(&mut expected)
+            .is_ok());
+        assert!(File::open(samfile)
+            .unwrap()
+            .read_to_end(&mut written)
+            .is_ok());
         assert_eq!(expected, written);
     }
 }